@@ -0,0 +1,49 @@
+//! Optional `oggify/config.toml` in the platform config directory, providing defaults for
+//! settings that would otherwise have to be passed on every invocation. CLI flags always
+//! take precedence over the config file.
+
+use std::path::PathBuf;
+
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::Quality;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    pub quality: Option<Quality>,
+    pub output_dir: Option<String>,
+    pub template: Option<String>,
+    pub ffmpeg_path: Option<String>,
+    pub jobs: Option<u32>,
+    pub cache_dir: Option<String>,
+}
+
+/// Loads `oggify/config.toml` from the platform config directory (e.g. `~/.config` on
+/// Linux, `~/Library/Application Support` on macOS). Returns the default (empty) config
+/// if there's no file there, or if it can't be parsed.
+pub fn load() -> Config {
+    let Some(path) = config_path() else {
+        return Config::default();
+    };
+
+    let Ok(contents) = std::fs::read_to_string(&path) else {
+        return Config::default();
+    };
+
+    match toml::from_str(&contents) {
+        Ok(config) => {
+            info!("Loaded config from {}", path.display());
+            config
+        }
+        Err(e) => {
+            warn!("Could not parse config file {}: {e}", path.display());
+            Config::default()
+        }
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("oggify").join("config.toml"))
+}
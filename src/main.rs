@@ -1,4 +1,7 @@
+extern crate base64;
+extern crate dirs;
 extern crate env_logger;
+extern crate indicatif;
 extern crate librespot_audio;
 extern crate librespot_core;
 extern crate librespot_metadata;
@@ -6,29 +9,40 @@ extern crate librespot_metadata;
 extern crate log;
 extern crate regex;
 extern crate scoped_threadpool;
+extern crate serde;
 extern crate tokio;
+extern crate toml;
+
+mod config;
+mod naming;
+mod transcode;
+
+use naming::{render_output_path, TemplateFields};
+use transcode::{resolve_ffmpeg_path, transcode_flac, transcode_mp3, OutputFormat};
 
-use std::env;
 use std::io::Write;
 use std::io::{self, BufRead, Read, Result};
-use std::process::{Command, Stdio};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use env_logger::{Builder, Env};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use librespot_audio::{AudioDecrypt, AudioFile};
 use librespot_core::authentication::Credentials;
 use librespot_core::config::SessionConfig;
 use librespot_core::session::Session;
-use librespot_core::spotify_id::SpotifyId;
+use librespot_core::spotify_id::{SpotifyId, SpotifyAudioType};
 use librespot_core::{SpotifyUri};
 
 use librespot_core::cache::Cache;
 use librespot_core::Error;
 
 
-use librespot_metadata::{Album, Artist, Metadata, Track};
+use librespot_metadata::{Album, Artist, Episode, Metadata, Playlist, Show, Track};
 use librespot_metadata::audio::{AudioFileFormat};
+use librespot_metadata::image::{Image, ImageSize};
 use regex::Regex;
 use scoped_threadpool::Pool;
 
@@ -48,11 +62,109 @@ struct Cli {
     #[clap(flatten)]
     group: Group,
 
+    /// Number of tracks to download concurrently. Defaults to the config file's `jobs`,
+    /// then 1.
+    #[clap(long)]
+    jobs: Option<u32>,
+
+    /// Source format/quality preset to request from Spotify. Defaults to the config
+    /// file's `quality`, then ogg-only.
+    #[clap(long, value_enum)]
+    quality: Option<Quality>,
+
+    /// Don't fetch and embed cover art
+    #[clap(long)]
+    no_cover: bool,
+
+    /// Output container/codec to produce
+    #[clap(long, value_enum, default_value_t = OutputFormat::Mp3)]
+    output_format: OutputFormat,
+
+    /// Path to the ffmpeg binary, used for --output-format mp3. Falls back to the config
+    /// file's `ffmpeg-path`, then the OGGIFY_FFMPEG_PATH env var, then PATH.
+    #[clap(long)]
+    ffmpeg_path: Option<String>,
+
+    /// MP3 bitrate in kbps, used for --output-format mp3
+    #[clap(long, default_value_t = 192)]
+    bitrate: u32,
+
+    /// Directory to write downloads under. Defaults to the config file's `output-dir`,
+    /// then "output".
+    #[clap(long)]
+    output_dir: Option<String>,
+
+    /// Output filename template, relative to --output-dir. Supports {artist}, {album},
+    /// {title}, {tracknumber}, {year} and {track_id}; '/' creates subdirectories. Defaults
+    /// to the config file's `template`, then "{artist} - {title}".
+    #[clap(long)]
+    template: Option<String>,
+
+    /// Directory holding the librespot credentials cache. Defaults to the config file's
+    /// `cache-dir`, then ".cache".
+    #[clap(long)]
+    cache_dir: Option<String>,
+
     /// Optional name to operate on
     name: Option<String>,
 
 }
 
+/// Source format preset, ordered from the format each variant prefers most to least.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Quality {
+    /// Vorbis only: 320k, falling back to 160k then 96k.
+    OggOnly,
+    /// Highest bitrate available in either Vorbis or MP3.
+    BestBitrate,
+    /// MP3 only: 320k, falling back to 256k, 160k then 96k.
+    Mp3Only,
+}
+
+impl Quality {
+    /// The formats this preset will accept, most preferred first.
+    fn formats(self) -> &'static [AudioFileFormat] {
+        use AudioFileFormat::*;
+        match self {
+            Quality::OggOnly => &[OGG_VORBIS_320, OGG_VORBIS_160, OGG_VORBIS_96],
+            Quality::BestBitrate => &[
+                OGG_VORBIS_320,
+                MP3_320,
+                MP3_256,
+                OGG_VORBIS_160,
+                MP3_160,
+                OGG_VORBIS_96,
+                MP3_96,
+            ],
+            Quality::Mp3Only => &[MP3_320, MP3_256, MP3_160, MP3_96],
+        }
+    }
+}
+
+/// The nominal bitrate (in kbps) for a given `AudioFileFormat`, used as the `AudioFile::open`
+/// bitrate hint.
+fn bitrate_for(format: AudioFileFormat) -> usize {
+  use AudioFileFormat::*;
+  match format {
+    OGG_VORBIS_320 | MP3_320 => 320,
+    OGG_VORBIS_160 | MP3_160 => 160,
+    OGG_VORBIS_96 | MP3_96 => 96,
+    MP3_256 => 256,
+    _ => 160,
+  }
+}
+
+/// Whether `format` is an MP3-encoded source file, as opposed to Ogg Vorbis.
+///
+/// The download/tag/transcode pipeline in `process_media` assumes a Vorbis container
+/// end-to-end (`oggvorbismeta::replace_comment_header`, and for `--output-format flac`,
+/// `lewton::inside_ogg::OggStreamReader`), so MP3 source files can't go through it yet.
+fn is_mp3_format(format: AudioFileFormat) -> bool {
+    use AudioFileFormat::*;
+    matches!(format, MP3_320 | MP3_256 | MP3_160 | MP3_96)
+}
+
 #[derive(Debug, clap::Args)]
 #[group(required = true, multiple = false)]
 pub struct Group {
@@ -67,106 +179,403 @@ pub struct Group {
 }
 
 const CACHE: &str = ".cache";
-const CACHE_FILES: &str = ".cache/files";
 const OUTPUT_DIR: &str = "output";
 
-#[tokio::main]
-async fn main() {
-  Builder::from_env(Env::default().default_filter_or("info")).init();
+/// Disambiguates intermediate filenames so concurrent downloads never share one.
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Reads one Spotify URL per line from `path`, or from stdin when `path == "-"`.
+/// Blank lines are ignored.
+fn read_urls(path: &str) -> Vec<String> {
+  let reader: Box<dyn BufRead> = if path == "-" {
+    Box::new(io::BufReader::new(io::stdin()))
+  } else {
+    Box::new(io::BufReader::new(
+      File::open(path).expect("Could not open --urls file"),
+    ))
+  };
 
-  let cli = Cli::parse();
+  reader
+    .lines()
+    .filter_map(|line| line.ok())
+    .map(|line| line.trim().to_string())
+    .filter(|line| !line.is_empty())
+    .collect()
+}
 
-  let args: Vec<_> = env::args().collect();
+/// Matches `open.spotify.com/{track,album,playlist,artist,episode,show}/ID` links.
+fn spotify_url_re() -> Regex {
+  Regex::new(r"open\.spotify\.com/(track|album|playlist|artist|episode|show)/([[:alnum:]]+)")
+    .unwrap()
+}
 
+/// A single piece of media resolved from a URL: either a music track or a podcast episode.
+/// They're fetched and decrypted the same way, but carry different metadata.
+enum MediaId {
+    Track(String),
+    Episode(String),
+}
 
-  let url_input = cli.group.url.as_deref().unwrap();
+/// Expands a Spotify URL into the media it contains, preserving album/playlist/show order.
+/// Track and episode URLs resolve to themselves; album/playlist URLs expand to their tracks;
+/// artist URLs expand to the artist's top tracks (the US list, else the first one listed),
+/// or, when the artist has no top tracks, to the tracks of each of the artist's albums; show
+/// URLs expand to their episodes.
+async fn resolve_media_ids(session: &Session, url: &str) -> Vec<MediaId> {
+  let spotify_url = spotify_url_re();
+  let Some(cap) = spotify_url.captures(url) else {
+    error!("Only Spotify track/album/playlist/artist/episode/show URLs are supported currently: {url}");
+    return vec![];
+  };
 
-  info!(
-    "URL: {}",
-    url_input
-  );
-  
-  let spotify_url = Regex::new(r"open\.spotify\.com/track/([[:alnum:]]+)").unwrap();
-  if !spotify_url.is_match(url_input) {
-      error!("Only Spotify track URLs are supported currently.");
-      return;
+  let kind = cap.get(1).map(|m| m.as_str()).unwrap();
+  let id = cap.get(2).map(|m| m.as_str()).unwrap();
+
+  match kind {
+    "track" => vec![MediaId::Track(id.to_string())],
+    "episode" => vec![MediaId::Episode(id.to_string())],
+    "album" => {
+      let Ok(uri) = SpotifyUri::from_uri(&format!("spotify:album:{}", id)) else {
+        error!("Not a valid Spotify album URI: {id}");
+        return vec![];
+      };
+      let album = match Album::get(session, &uri).await {
+        Ok(album) => album,
+        Err(e) => {
+          error!("Could not fetch album {id}, skipping: {e}");
+          return vec![];
+        }
+      };
+      album
+        .discs
+        .iter()
+        .flat_map(|disc| disc.tracks.iter())
+        .filter_map(|id| id.to_base62().ok())
+        .map(MediaId::Track)
+        .collect()
+    }
+    "playlist" => {
+      let Ok(uri) = SpotifyUri::from_uri(&format!("spotify:playlist:{}", id)) else {
+        error!("Not a valid Spotify playlist URI: {id}");
+        return vec![];
+      };
+      let playlist = match Playlist::get(session, &uri).await {
+        Ok(playlist) => playlist,
+        Err(e) => {
+          error!("Could not fetch playlist {id}, skipping: {e}");
+          return vec![];
+        }
+      };
+      playlist
+        .tracks
+        .iter()
+        .filter_map(|id| id.to_base62().ok())
+        .map(MediaId::Track)
+        .collect()
+    }
+    "show" => {
+      let Ok(uri) = SpotifyUri::from_uri(&format!("spotify:show:{}", id)) else {
+        error!("Not a valid Spotify show URI: {id}");
+        return vec![];
+      };
+      let show = match Show::get(session, &uri).await {
+        Ok(show) => show,
+        Err(e) => {
+          error!("Could not fetch show {id}, skipping: {e}");
+          return vec![];
+        }
+      };
+      show.episodes
+        .iter()
+        .filter_map(|id| id.to_base62().ok())
+        .map(MediaId::Episode)
+        .collect()
+    }
+    "artist" => {
+      let Ok(uri) = SpotifyUri::from_uri(&format!("spotify:artist:{}", id)) else {
+        error!("Not a valid Spotify artist URI: {id}");
+        return vec![];
+      };
+      let artist = match Artist::get(session, &uri).await {
+        Ok(artist) => artist,
+        Err(e) => {
+          error!("Could not fetch artist {id}, skipping: {e}");
+          return vec![];
+        }
+      };
+
+      let top_tracks = artist
+        .top_tracks
+        .iter()
+        .find(|t| t.country == "US")
+        .or_else(|| artist.top_tracks.first());
+
+      if let Some(top_tracks) = top_tracks {
+        top_tracks
+          .tracks
+          .iter()
+          .filter_map(|id| id.to_base62().ok())
+          .map(MediaId::Track)
+          .collect()
+      } else {
+        let mut track_ids = Vec::new();
+        for album_group in &artist.albums {
+          for album_id in &album_group.0 {
+            let Ok(album_uri) = album_id.to_uri() else {
+              error!("Could not build a URI for album {album_id:?}, skipping");
+              continue;
+            };
+            match Album::get(session, &album_uri).await {
+              Ok(album) => track_ids.extend(
+                album
+                  .discs
+                  .iter()
+                  .flat_map(|disc| disc.tracks.iter())
+                  .filter_map(|id| id.to_base62().ok())
+                  .map(MediaId::Track),
+              ),
+              Err(e) => error!("Could not fetch album {album_id:?}, skipping: {e}"),
+            }
+          }
+        }
+        track_ids
+      }
+    }
+    _ => unreachable!("regex only matches track/album/playlist/artist/episode/show"),
   }
+}
 
-  let track_id = spotify_url
-      .captures(url_input)
-      .and_then(|cap| cap.get(1))
-      .map(|m| m.as_str())
-      .unwrap();
+/// What gets downloaded, which Vorbis comments describe it, and what its output filename is.
+struct MediaInfo {
+    id: SpotifyId,
+    display_id: String,
+    files: std::collections::HashMap<AudioFileFormat, librespot_metadata::FileId>,
+    tags: Vec<(&'static str, String)>,
+    template_fields: TemplateFields,
+    cover: Option<Vec<u8>>,
+}
 
-  info!(
-    "Track ID: {}",
-    track_id
-  );
-  
+/// The largest cover image in `covers`, approximating pixel dimensions from `ImageSize`
+/// since the metadata only carries Spotify's small/default/large/xlarge buckets.
+fn largest_cover(covers: &[Image]) -> Option<&Image> {
+  fn rank(size: ImageSize) -> u32 {
+    match size {
+      ImageSize::DEFAULT => 300,
+      ImageSize::SMALL => 64,
+      ImageSize::LARGE => 640,
+      ImageSize::XLARGE => 1000,
+    }
+  }
+  covers.iter().max_by_key(|image| rank(image.size))
+}
+
+/// Fetches a cover image's JPEG bytes through the session's CDN/image channel.
+async fn fetch_cover(session: &Session, covers: &[Image]) -> Option<Vec<u8>> {
+  let cover = largest_cover(covers)?;
+  match session.spclient().get_image(&cover.id).await {
+    Ok(data) => Some(data.to_vec()),
+    Err(e) => {
+      warn!("Could not fetch cover art: {e}");
+      None
+    }
+  }
+}
+
+/// Builds a `METADATA_BLOCK_PICTURE` Vorbis comment value (base64-encoded FLAC picture
+/// block, picture type 3 = front cover) from raw JPEG bytes.
+fn vorbis_picture_block(jpeg: &[u8]) -> String {
+  let mut block = Vec::new();
+  block.extend_from_slice(&3u32.to_be_bytes()); // picture type: front cover
+  let mime = b"image/jpeg";
+  block.extend_from_slice(&(mime.len() as u32).to_be_bytes());
+  block.extend_from_slice(mime);
+  block.extend_from_slice(&0u32.to_be_bytes()); // description length
+  block.extend_from_slice(&0u32.to_be_bytes()); // width (unknown, left as 0)
+  block.extend_from_slice(&0u32.to_be_bytes()); // height (unknown, left as 0)
+  block.extend_from_slice(&0u32.to_be_bytes()); // color depth (unknown, left as 0)
+  block.extend_from_slice(&0u32.to_be_bytes()); // colors used (0 = non-indexed)
+  block.extend_from_slice(&(jpeg.len() as u32).to_be_bytes());
+  block.extend_from_slice(jpeg);
+  base64::encode(&block)
+}
+
+/// Fetches track metadata and builds the artist/album/title/tracknumber/date tags oggify
+/// has always written. Returns `None` (after logging why) if the track can't be fetched,
+/// so one bad track in a batch doesn't abort the rest.
+async fn fetch_track_info(session: &Session, track_id: &str, want_cover: bool) -> Option<MediaInfo> {
   let spotify_uri = format!("spotify:track:{}", track_id);
+  let uri = SpotifyUri::from_uri(&spotify_uri).ok()?;
+  let id_str = uri.to_id().ok()?;
+  let id = SpotifyId::from_base62(&id_str).ok()?;
 
-  info!(
-    "Spotify URI: {}",
-    spotify_uri
-  );
+  info!("Getting track metadata...");
+  info!("Track URI: {}", uri);
 
-  // let core = tokio::runtime::Runtime::new().unwrap();
-  let session_config = SessionConfig::default();
+  let track = match Track::get(session, &uri).await {
+    Ok(track) => track,
+    Err(e) => {
+      error!("Could not fetch track {track_id}, skipping: {e}");
+      return None;
+    }
+  };
 
-  let cache = Cache::new(Some(CACHE), Some(CACHE), Some(CACHE_FILES), None).unwrap();
-  let credentials = cache
-      .credentials()
-      .ok_or(Error::unavailable("credentials not cached"))
-      .or_else(|_| {
-          librespot_oauth::OAuthClientBuilder::new(
-              &session_config.client_id,
-              "http://127.0.0.1:8898/login",
-              vec!["streaming"],
-          )
-          .open_in_browser()
-          .build()?
-          .get_access_token()
-          .map(|t| Credentials::with_access_token(t.access_token))
-      }).unwrap();
+  let artists = track.artists.iter().map(|a| a.name.clone()).collect::<Vec<_>>();
+  info!("Artists: {}", artists.join(", "));
 
-  let session = Session::new(session_config, Some(cache));
-  info!("Connecting ...");
+  let track_name = track.name.clone();
+  info!("Track name: {}", track_name);
 
-  match session.connect(credentials, true).await {
-      Ok(()) => info!("Session username: {:#?}", session.username()),
-      Err(e) => {
-          println!("Error connecting: {e}");
-          return;
-      }
+  let track_id = track.id.to_base62().ok()?;
+  info!("Track id: {}", track_id);
+
+  let mut tags = Vec::new();
+  for artist in &artists {
+    tags.push(("artist", artist.clone()));
+  }
+  tags.push(("album", track.album.name.to_string()));
+  tags.push(("tracknumber", track.number.to_string()));
+  tags.push(("title", track_name.to_string()));
+  tags.push(("date", track.album.date.year().to_string()));
+
+  let cover = if want_cover {
+    fetch_cover(session, &track.album.covers).await
+  } else {
+    None
   };
-  
-  info!("Connected!");
 
-  let mut threadpool = Pool::new(1);
+  Some(MediaInfo {
+    id,
+    display_id: track_id.clone(),
+    files: track.files,
+    tags,
+    template_fields: TemplateFields {
+      artist: artists.join(", "),
+      album: track.album.name.to_string(),
+      title: track_name,
+      tracknumber: track.number.to_string(),
+      year: track.album.date.year().to_string(),
+      track_id,
+    },
+    cover,
+  })
+}
 
-  let uri = SpotifyUri::from_uri(&spotify_uri).unwrap();
+/// Fetches episode metadata and builds the show/publisher/description/date tags that take
+/// the place of the track/album/artist tags for podcast episodes. Returns `None` (after
+/// logging why) if the episode can't be fetched, so one bad episode in a batch doesn't
+/// abort the rest.
+async fn fetch_episode_info(session: &Session, episode_id: &str, want_cover: bool) -> Option<MediaInfo> {
+  let spotify_uri = format!("spotify:episode:{}", episode_id);
+  let uri = SpotifyUri::from_uri(&spotify_uri).ok()?;
+  let id_str = uri.to_id().ok()?;
+  let mut id = SpotifyId::from_base62(&id_str).ok()?;
+  id.audio_type = SpotifyAudioType::Podcast;
+
+  info!("Getting episode metadata...");
+  info!("Episode URI: {}", uri);
+
+  let episode = match Episode::get(session, &uri).await {
+    Ok(episode) => episode,
+    Err(e) => {
+      error!("Could not fetch episode {episode_id}, skipping: {e}");
+      return None;
+    }
+  };
 
-  let id_str = uri.to_id().unwrap();
+  let episode_name = episode.name.clone();
+  info!("Episode name: {}", episode_name);
 
-  let id = SpotifyId::from_base62(&id_str).unwrap();
+  let episode_id = episode.id.to_base62().ok()?;
+  info!("Episode id: {}", episode_id);
 
-  info!("Getting track metadata...");
-  info!("Track URI: {}", uri);
+  let tags = vec![
+    ("artist", episode.show.publisher.clone()),
+    ("album", episode.show.name.clone()),
+    ("title", episode_name.clone()),
+    ("description", episode.description.clone()),
+    ("date", episode.publish_time.year().to_string()),
+  ];
 
-  let track = Track::get(&session, &uri).await.unwrap();
+  let cover = if want_cover {
+    fetch_cover(session, &episode.show.covers).await
+  } else {
+    None
+  };
 
-  let artists = track.artists.iter().map(|a| a.name.as_str()).collect::<Vec<_>>();
-  info!("Artists: {}", artists.join(", "));
-  
-  let track_name = track.name.clone();
-  info!("Track name: {}", track_name);
+  Some(MediaInfo {
+    id,
+    display_id: episode_id.clone(),
+    files: episode.files,
+    tags,
+    template_fields: TemplateFields {
+      artist: episode.show.publisher.clone(),
+      album: episode.show.name.clone(),
+      title: episode_name,
+      tracknumber: "0".to_string(),
+      year: episode.publish_time.year().to_string(),
+      track_id: episode_id,
+    },
+    cover,
+  })
+}
 
-  let track_id = track.id.to_base62().unwrap();
-  info!("Track id: {}", track_id);
+/// Output encoding options shared by every track/episode in a run.
+struct DownloadOptions {
+    quality: Quality,
+    want_cover: bool,
+    output_format: OutputFormat,
+    ffmpeg_path: Option<std::path::PathBuf>,
+    bitrate: u32,
+    output_dir: String,
+    template: String,
+}
+
+/// Downloads, decrypts, tags and transcodes a single track or episode, reporting progress on `pb`.
+async fn process_media(session: &Session, media: &MediaId, opts: &DownloadOptions, pb: &ProgressBar) {
+  let log_id = match media {
+      MediaId::Track(id) => id.as_str(),
+      MediaId::Episode(id) => id.as_str(),
+  };
+  pb.set_message(format!("{log_id}: fetching metadata"));
+
+  let info = match media {
+      MediaId::Track(track_id) => fetch_track_info(session, track_id, opts.want_cover).await,
+      MediaId::Episode(episode_id) => {
+          fetch_episode_info(session, episode_id, opts.want_cover).await
+      }
+  };
+
+  let info = match info {
+      Some(info) => info,
+      None => {
+          pb.finish_with_message(format!("skipped (metadata fetch failed): {log_id}"));
+          return;
+      }
+  };
+
+  let output_path = render_output_path(
+      &opts.output_dir,
+      &opts.template,
+      &info.template_fields,
+      opts.output_format.extension(),
+  );
+
+  if output_path.exists() {
+      info!("Already downloaded, skipping: {}", output_path.display());
+      pb.finish_with_message(format!("skipped (exists): {}", output_path.display()));
+      return;
+  }
+
+  if let Some(parent) = output_path.parent() {
+      std::fs::create_dir_all(parent).expect("Could not create output directory");
+  }
+
+  // Unique per download so concurrent jobs never clobber each other's intermediate files.
+  let temp_id = format!("{}-{}", info.display_id, TEMP_COUNTER.fetch_add(1, Ordering::Relaxed));
 
   info!(
     "File formats: {}",
-    track
+    info
       .files
       .keys()
       .map(|filetype| format!("{:?}", filetype))
@@ -174,14 +583,37 @@ async fn main() {
       .join(" ")
   );
 
-  let file_id = track
-    .files
-    .get(&AudioFileFormat::OGG_VORBIS_320)
-    .or(track.files.get(&AudioFileFormat::OGG_VORBIS_160))
-    .or(track.files.get(&AudioFileFormat::OGG_VORBIS_96))
-    .expect("Could not find a OGG_VORBIS format for the track.");
+  let selected = opts
+      .quality
+      .formats()
+      .iter()
+      .find_map(|format| info.files.get(format).map(|file_id| (*format, *file_id)));
+
+  let (format, file_id) = match selected {
+      Some(found) => found,
+      None => {
+          error!(
+              "None of the formats for --quality {:?} are available for {}",
+              opts.quality, info.display_id
+          );
+          pb.finish_with_message(format!("skipped (no matching format): {}", info.display_id));
+          return;
+      }
+  };
+
+  // The tagging/transcode pipeline below assumes an Ogg Vorbis container throughout; an MP3
+  // source file would need ID3 tagging and a separate transcode path, which don't exist yet.
+  if is_mp3_format(format) {
+      error!(
+          "{}: selected format {:?} is MP3-encoded, but oggify can only tag/transcode Vorbis \
+           sources right now; try --quality ogg-only",
+          info.display_id, format
+      );
+      pb.finish_with_message(format!("skipped (MP3 source unsupported): {}", info.display_id));
+      return;
+  }
 
-  let key = match session.audio_key().request(id, *file_id).await {
+  let key = match session.audio_key().request(info.id, file_id).await {
       Ok(key) => Some(key),
       Err(e) => {
           warn!("Unable to load key, continuing without decryption: {e}");
@@ -189,53 +621,59 @@ async fn main() {
       }
   };
 
-  let fname = format!("{}/{}.ogg", OUTPUT_DIR, track_id);
-  info!("Writing decrypted track to {}", fname);
+  let fname = format!("{}/.{}.ogg", opts.output_dir, temp_id);
+  info!("Writing decrypted media to {}", fname);
+  pb.set_message(format!("{}: downloading", info.display_id));
 
-  let mut encrypted_file = AudioFile::open(&session, *file_id, 320).await
-    .unwrap();
+  macro_rules! skip {
+      ($reason:expr, $($arg:tt)*) => {{
+          error!($($arg)*);
+          pb.finish_with_message(format!("skipped ({}): {}", $reason, info.display_id));
+          return;
+      }};
+  }
+
+  let mut encrypted_file = match AudioFile::open(session, file_id, bitrate_for(format)).await {
+      Ok(file) => file,
+      Err(e) => skip!("open failed", "Could not open audio file for {}: {e}", info.display_id),
+  };
 
   let mut buffer = Vec::new();
-  let mut read_all: Result<usize> = Ok(0);
-  let fetched = AtomicBool::new(false);
-  threadpool.scoped(|scope| {
-    scope.execute(|| {
-      read_all = encrypted_file.read_to_end(&mut buffer);
-      fetched.store(true, Ordering::Release);
-    });
-    while !fetched.load(Ordering::Acquire) {
-      // tokio::time::sleep(Duration::from_millis(100)).await;
-    }
-  });
+  if let Err(e) = encrypted_file.read_to_end(&mut buffer) {
+      skip!("read failed", "Cannot read file stream for {}: {e}", info.display_id);
+  }
 
-  read_all.expect("Cannot read file stream");
   let mut decrypted_buffer = Vec::new();
-  AudioDecrypt::new(key, &buffer[..])
-    .read_to_end(&mut decrypted_buffer)
-    .expect("Cannot decrypt stream");
+  if let Err(e) = AudioDecrypt::new(key, &buffer[..]).read_to_end(&mut decrypted_buffer) {
+      skip!("decrypt failed", "Cannot decrypt stream for {}: {e}", info.display_id);
+  }
 
-  std::fs::write(&fname, &decrypted_buffer[0xa7..]).expect("Cannot write decrypted track");
+  if let Err(e) = std::fs::write(&fname, &decrypted_buffer[0xa7..]) {
+      skip!("write failed", "Cannot write decrypted media to {fname}: {e}");
+  }
 
-  let mut f_in_disk = File::open(fname).expect("Can't open file");
+  let mut f_in_disk = match File::open(&fname) {
+      Ok(file) => file,
+      Err(e) => skip!("read failed", "Can't reopen {fname}: {e}"),
+  };
   let mut f_in_ram: Vec<u8> = vec![];
 
-  std::io::copy(&mut f_in_disk, &mut f_in_ram).unwrap();
-  
-  let file_out = format!("{}/{}-tagged.ogg", OUTPUT_DIR, track_id);
+  if let Err(e) = std::io::copy(&mut f_in_disk, &mut f_in_ram) {
+      skip!("read failed", "Can't read {fname}: {e}");
+  }
+
+  let file_out = format!("{}/.{}-tagged.ogg", opts.output_dir, temp_id);
 
   let f_in = Cursor::new(&f_in_ram);
   let mut new_comment = CommentHeader::new();
 
   new_comment.set_vendor("Ogg");
-  for artist in &artists {
-      new_comment.add_tag_single("artist", artist.to_string());
+  for (tag, value) in &info.tags {
+      new_comment.add_tag_single(tag, value.clone());
+  }
+  if let Some(cover) = &info.cover {
+      new_comment.add_tag_single("METADATA_BLOCK_PICTURE", vorbis_picture_block(cover));
   }
-
-  new_comment.add_tag_single("album", track.album.name.to_string());
-  new_comment.add_tag_single("tracknumber", track.number.to_string());
-  new_comment.add_tag_single("title", track_name.to_string());
-  // Add year from date
-  new_comment.add_tag_single("date", track.album.date.year().to_string());
 
   let tag_names = new_comment.get_tag_names();
   info!("New tags: {tag_names:?}");
@@ -244,53 +682,217 @@ async fn main() {
   }
 
   info!("Insert new comments");
-  let mut f_out = replace_comment_header(f_in, &new_comment).expect("Can't write comments");
+  let mut f_out = match replace_comment_header(f_in, &new_comment) {
+      Ok(f_out) => f_out,
+      Err(e) => {
+          let _ = std::fs::remove_file(&fname);
+          skip!("tagging failed", "Can't write comments for {}: {e}", info.display_id);
+      }
+  };
 
   info!("Save to disk");
-  let mut f_out_disk = File::create(file_out).unwrap();
-  std::io::copy(&mut f_out, &mut f_out_disk).unwrap();
+  let write_result = File::create(&file_out)
+      .and_then(|mut f_out_disk| std::io::copy(&mut f_out, &mut f_out_disk));
+  if let Err(e) = write_result {
+      let _ = std::fs::remove_file(&fname);
+      skip!("write failed", "Cannot write tagged file to {file_out}: {e}");
+  }
 
-  let ffmpeg_cmd = format!(
-      "/opt/homebrew/bin/ffmpeg -i {}/{}-tagged.ogg -map_metadata 0:s:0 -write_id3v2 1 -id3v2_version 3 {}/{}.mp3",
-      OUTPUT_DIR, track_id, OUTPUT_DIR, track_id
-  );
+  pb.set_message(format!("{}: encoding ({:?})", info.display_id, opts.output_format));
 
-  let mut cmd = Command::new("/opt/homebrew/bin/ffmpeg");
-
-  let output_mp3 = format!("{}/{} - {}.mp3", OUTPUT_DIR, artists.join(", "), track_name.to_string());
-
-  cmd.arg("-y")
-    .arg("-i")
-    .arg(format!("{}/{}-tagged.ogg", OUTPUT_DIR, track_id))
-    .arg("-map_metadata")
-    .arg("0:s:0")
-    .arg("-write_id3v2")
-    .arg("1")
-    .arg("-id3v2_version")
-    .arg("3")
-    .arg("-b:a")
-    .arg("192k")
-    .arg(output_mp3.clone());
-
-  cmd.stdin(Stdio::piped());
-
-  let mut child = cmd.spawn().expect("Could not run helper program");
-  assert!(
-    child
-      .wait()
-      .expect("Out of ideas for error messages")
-      .success(),
-    "Helper script returned an error"
-  );
+  match opts.output_format {
+      OutputFormat::Ogg => {
+          if let Err(e) = std::fs::rename(&file_out, &output_path) {
+              let _ = std::fs::remove_file(&fname);
+              let _ = std::fs::remove_file(&file_out);
+              skip!("move failed", "Could not move tagged file to output: {e}");
+          }
+      }
+      OutputFormat::Flac => {
+          if !transcode_flac(Path::new(&file_out), &info.tags, info.cover.as_deref(), &output_path) {
+              let _ = std::fs::remove_file(&fname);
+              let _ = std::fs::remove_file(&file_out);
+              skip!("transcode failed", "FLAC transcode failed for {}", info.display_id);
+          }
+          if let Err(e) = std::fs::remove_file(&file_out) {
+              warn!("Could not remove tagged ogg file {file_out}: {e}");
+          }
+      }
+      OutputFormat::Mp3 => {
+          let ffmpeg_path = match &opts.ffmpeg_path {
+              Some(path) => path.clone(),
+              None => {
+                  let _ = std::fs::remove_file(&fname);
+                  let _ = std::fs::remove_file(&file_out);
+                  skip!(
+                      "no ffmpeg",
+                      "Could not find ffmpeg (set --ffmpeg-path, OGGIFY_FFMPEG_PATH, or add it to PATH)"
+                  );
+              }
+          };
+
+          let cover_jpg = format!("{}/.{}-cover.jpg", opts.output_dir, temp_id);
+          if let Some(cover) = &info.cover {
+              if let Err(e) = std::fs::write(&cover_jpg, cover) {
+                  let _ = std::fs::remove_file(&fname);
+                  let _ = std::fs::remove_file(&file_out);
+                  skip!("write failed", "Could not write cover art to disk: {e}");
+              }
+          }
+
+          let transcoded = transcode_mp3(
+              &ffmpeg_path,
+              Path::new(&file_out),
+              info.cover.is_some().then(|| Path::new(cover_jpg.as_str())),
+              opts.bitrate,
+              &output_path,
+          );
+
+          if let Err(e) = std::fs::remove_file(&file_out) {
+              warn!("Could not remove tagged ogg file {file_out}: {e}");
+          }
+          if info.cover.is_some() {
+              if let Err(e) = std::fs::remove_file(&cover_jpg) {
+                  warn!("Could not remove temporary cover art file {cover_jpg}: {e}");
+              }
+          }
+
+          if !transcoded {
+              let _ = std::fs::remove_file(&fname);
+              skip!("transcode failed", "MP3 transcode failed for {}", info.display_id);
+          }
+      }
+  }
+
+  // Remove the original (untagged) ogg file
+  if let Err(e) = std::fs::remove_file(&fname) {
+      warn!("Could not remove original ogg file {fname}: {e}");
+  }
+
+  info!("Done, written to: {}", output_path.display());
+  pb.finish_with_message(format!("done: {}", output_path.display()));
+}
+
+#[tokio::main]
+async fn main() {
+  Builder::from_env(Env::default().default_filter_or("info")).init();
+
+  let cli = Cli::parse();
+  let file_config = config::load();
+
+  let jobs = cli.jobs.or(file_config.jobs).unwrap_or(1);
+  let quality = cli.quality.or(file_config.quality).unwrap_or(Quality::OggOnly);
+  let ffmpeg_path_override = cli.ffmpeg_path.clone().or(file_config.ffmpeg_path);
+  let output_dir = cli.output_dir.clone().or(file_config.output_dir).unwrap_or_else(|| OUTPUT_DIR.to_string());
+  let template = cli.template.clone().or(file_config.template).unwrap_or_else(|| "{artist} - {title}".to_string());
+  let cache_dir = cli.cache_dir.clone().or(file_config.cache_dir).unwrap_or_else(|| CACHE.to_string());
+
+  let urls: Vec<String> = if let Some(urls_path) = cli.group.urls.as_deref() {
+      read_urls(urls_path)
+  } else {
+      vec![cli.group.url.clone().unwrap()]
+  };
+
+  if urls.is_empty() {
+      error!("No URLs to process.");
+      return;
+  }
+
+  std::fs::create_dir_all(&output_dir).expect("Could not create output directory");
+
+  let session_config = SessionConfig::default();
+
+  let cache_files = format!("{}/files", cache_dir);
+  let cache = Cache::new(Some(&cache_dir), Some(&cache_dir), Some(&cache_files), None).unwrap();
+  let credentials = cache
+      .credentials()
+      .ok_or(Error::unavailable("credentials not cached"))
+      .or_else(|_| {
+          librespot_oauth::OAuthClientBuilder::new(
+              &session_config.client_id,
+              "http://127.0.0.1:8898/login",
+              vec!["streaming"],
+          )
+          .open_in_browser()
+          .build()?
+          .get_access_token()
+          .map(|t| Credentials::with_access_token(t.access_token))
+      }).unwrap();
+
+  let session = Session::new(session_config, Some(cache));
+  info!("Connecting ...");
+
+  match session.connect(credentials, true).await {
+      Ok(()) => info!("Session username: {:#?}", session.username()),
+      Err(e) => {
+          println!("Error connecting: {e}");
+          return;
+      }
+  };
 
-  // Remove the tagged ogg file
-  let tagged_ogg_file = format!("{}/{}-tagged.ogg", OUTPUT_DIR, track_id);
-  std::fs::remove_file(tagged_ogg_file).expect("Could not remove tagged ogg file");
+  info!("Connected!");
 
-  // Remove the original ogg file
-  let original_ogg_file = format!("{}/{}.ogg", OUTPUT_DIR, track_id);
-  std::fs::remove_file(original_ogg_file).expect("Could not remove original ogg file");
+  let ffmpeg_path = if cli.output_format == OutputFormat::Mp3 {
+      resolve_ffmpeg_path(ffmpeg_path_override.as_deref())
+  } else {
+      None
+  };
 
-  info!("Done, written to: {}", output_mp3);
+  if cli.output_format == OutputFormat::Mp3 && ffmpeg_path.is_none() {
+      warn!(
+          "Could not find ffmpeg (set --ffmpeg-path, the config file's ffmpeg-path, \
+           OGGIFY_FFMPEG_PATH, or add it to PATH); MP3 downloads will be skipped."
+      );
+  }
+
+  let opts = DownloadOptions {
+      quality,
+      want_cover: !cli.no_cover,
+      output_format: cli.output_format,
+      ffmpeg_path,
+      bitrate: cli.bitrate,
+      output_dir,
+      template,
+  };
 
-}
\ No newline at end of file
+  info!("Resolving URLs to tracks and episodes...");
+  let mut media_ids = Vec::new();
+  for url in &urls {
+      media_ids.extend(resolve_media_ids(&session, url).await);
+  }
+
+  if media_ids.is_empty() {
+      error!("No tracks or episodes to download.");
+      return;
+  }
+
+  let multi = MultiProgress::new();
+  let overall = multi.add(ProgressBar::new(media_ids.len() as u64));
+  overall.set_style(
+      ProgressStyle::with_template("{msg} [{bar:40}] {pos}/{len}")
+          .unwrap()
+          .progress_chars("=> "),
+  );
+  overall.set_message("oggify");
+
+  let handle = tokio::runtime::Handle::current();
+  let session = Arc::new(session);
+  let mut threadpool = Pool::new(jobs.max(1));
+
+  threadpool.scoped(|scope| {
+      for media in &media_ids {
+          let session = Arc::clone(&session);
+          let handle = handle.clone();
+          let track_pb = multi.add(ProgressBar::new_spinner());
+          track_pb.enable_steady_tick(Duration::from_millis(100));
+          let overall = overall.clone();
+          let opts = &opts;
+          scope.execute(move || {
+              handle.block_on(process_media(&session, media, opts, &track_pb));
+              overall.inc(1);
+          });
+      }
+  });
+
+  overall.finish_with_message("All downloads complete");
+}
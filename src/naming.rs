@@ -0,0 +1,122 @@
+//! Renders the `--template` output path, substituting placeholders and sanitizing each path
+//! component for filesystem-illegal characters.
+
+use std::path::PathBuf;
+
+/// The fields a `--template` string can reference.
+pub struct TemplateFields {
+    pub artist: String,
+    pub album: String,
+    pub title: String,
+    pub tracknumber: String,
+    pub year: String,
+    pub track_id: String,
+}
+
+/// Characters that are illegal (or awkward) in a path component on at least one of
+/// Linux/macOS/Windows.
+const ILLEGAL_CHARS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Replaces characters that aren't safe in a single path component with `_`, and trims the
+/// trailing dots/spaces Windows rejects.
+fn sanitize_component(component: &str) -> String {
+    let sanitized: String = component
+        .chars()
+        .map(|c| if ILLEGAL_CHARS.contains(&c) || c.is_control() { '_' } else { c })
+        .collect();
+    sanitized.trim_end_matches(['.', ' ']).to_string()
+}
+
+/// Substitutes `{artist}`, `{album}`, `{title}`, `{tracknumber}`, `{year}` and `{track_id}`
+/// in `template`, then sanitizes each `/`-separated component so the result is safe to use
+/// as a relative path under `output_dir`.
+pub fn render_output_path(
+    output_dir: &str,
+    template: &str,
+    fields: &TemplateFields,
+    extension: &str,
+) -> PathBuf {
+    let rendered = template
+        .replace("{artist}", &fields.artist)
+        .replace("{album}", &fields.album)
+        .replace("{title}", &fields.title)
+        .replace("{tracknumber}", &fields.tracknumber)
+        .replace("{year}", &fields.year)
+        .replace("{track_id}", &fields.track_id);
+
+    let mut components: Vec<String> = rendered
+        .split('/')
+        .map(sanitize_component)
+        .filter(|sanitized| !sanitized.is_empty())
+        .collect();
+
+    // Append the extension as a literal suffix rather than via `set_extension`, which would
+    // instead replace whatever follows the last `.` in the final component -- truncating any
+    // title/artist/album that itself contains a period (e.g. "Mr. Brightside" -> "Mr.ogg").
+    if let Some(last) = components.last_mut() {
+        last.push('.');
+        last.push_str(extension);
+    }
+
+    let mut path = PathBuf::from(output_dir);
+    for component in components {
+        path.push(component);
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> TemplateFields {
+        TemplateFields {
+            artist: "Artist".to_string(),
+            album: "Album".to_string(),
+            title: "Title".to_string(),
+            tracknumber: "1".to_string(),
+            year: "2024".to_string(),
+            track_id: "abc123".to_string(),
+        }
+    }
+
+    #[test]
+    fn replaces_illegal_characters() {
+        assert_eq!(sanitize_component("AC/DC: Back?"), "AC_DC_ Back_");
+        assert_eq!(sanitize_component("trailing. "), "trailing");
+    }
+
+    #[test]
+    fn dot_only_components_collapse_to_nothing() {
+        assert_eq!(sanitize_component("."), "");
+        assert_eq!(sanitize_component(".."), "");
+    }
+
+    #[test]
+    fn traversal_components_in_template_cant_escape_output_dir() {
+        let path = render_output_path("output", "../../{title}", &fields(), "ogg");
+        assert_eq!(path, PathBuf::from("output/Title.ogg"));
+    }
+
+    #[test]
+    fn titles_containing_a_period_are_not_truncated() {
+        let mut fields = fields();
+        fields.title = "Mr. Brightside".to_string();
+        let path = render_output_path("output", "{title}", &fields, "ogg");
+        assert_eq!(path, PathBuf::from("output/Mr. Brightside.ogg"));
+    }
+
+    #[test]
+    fn substitutes_all_placeholders() {
+        let path = render_output_path(
+            "output",
+            "{artist}/{album}/{tracknumber} - {title} ({year}) [{track_id}]",
+            &fields(),
+            "ogg",
+        );
+        assert_eq!(
+            path,
+            PathBuf::from("output/Artist/Album/1 - Title (2024) [abc123].ogg")
+        );
+    }
+}
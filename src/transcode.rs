@@ -0,0 +1,336 @@
+//! Output encoding: keeping the tagged Vorbis file as-is, transcoding to MP3 via an external
+//! ffmpeg binary, or transcoding to FLAC in-process with no external tools.
+
+use std::env;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use clap::ValueEnum;
+use log::error;
+
+/// Output container/codec to produce for each downloaded track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Keep the tagged Vorbis file as-is; no transcode, no external tools.
+    Ogg,
+    /// Transcode to MP3 via an external ffmpeg binary.
+    Mp3,
+    /// Transcode to lossless FLAC in-process (decode Vorbis, then encode FLAC).
+    Flac,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Ogg => "ogg",
+            OutputFormat::Mp3 => "mp3",
+            OutputFormat::Flac => "flac",
+        }
+    }
+}
+
+/// Resolves the ffmpeg binary to invoke for MP3 transcodes: an explicit `--ffmpeg-path`,
+/// then the `OGGIFY_FFMPEG_PATH` environment variable, then whatever `ffmpeg` is found on
+/// `PATH`. Returns `None` if none of those produced an existing file; an explicit path that
+/// doesn't exist also yields `None`, without consulting the env var or `PATH`.
+pub fn resolve_ffmpeg_path(cli_path: Option<&str>) -> Option<PathBuf> {
+    if let Some(path) = cli_path {
+        let path = PathBuf::from(path);
+        return path.is_file().then_some(path);
+    }
+
+    if let Ok(path) = env::var("OGGIFY_FFMPEG_PATH") {
+        let path = PathBuf::from(path);
+        if path.is_file() {
+            return Some(path);
+        }
+    }
+
+    let exe_name = if cfg!(windows) { "ffmpeg.exe" } else { "ffmpeg" };
+    env::var_os("PATH").and_then(|paths| {
+        env::split_paths(&paths)
+            .map(|dir| dir.join(exe_name))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory under the OS temp dir, removed on drop, so tests can plant fake `ffmpeg`
+    /// binaries without touching the real PATH.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("oggify-test-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn touch(&self, name: &str) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::write(&path, b"").unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // env::set_var affects the whole process, so cli/env/PATH precedence has to be a single
+    // test rather than three that could run concurrently and clobber each other's state.
+    #[test]
+    fn resolves_ffmpeg_path_in_precedence_order() {
+        let cli_dir = TempDir::new("cli");
+        let env_dir = TempDir::new("env");
+        let path_dir = TempDir::new("path");
+
+        let cli_ffmpeg = cli_dir.touch("ffmpeg");
+        let env_ffmpeg = env_dir.touch("ffmpeg");
+        let path_ffmpeg = path_dir.touch("ffmpeg");
+
+        let original_env = env::var_os("OGGIFY_FFMPEG_PATH");
+        let original_path = env::var_os("PATH");
+
+        env::set_var("OGGIFY_FFMPEG_PATH", &env_ffmpeg);
+        env::set_var("PATH", &path_dir.0);
+
+        // An explicit --ffmpeg-path wins over both the env var and PATH.
+        assert_eq!(
+            resolve_ffmpeg_path(Some(cli_ffmpeg.to_str().unwrap())),
+            Some(cli_ffmpeg.clone())
+        );
+
+        // A non-existent --ffmpeg-path yields None rather than being trusted blindly or
+        // silently replaced by the env var or PATH.
+        assert_eq!(
+            resolve_ffmpeg_path(Some(cli_dir.0.join("missing").to_str().unwrap())),
+            None
+        );
+
+        // With no --ffmpeg-path, OGGIFY_FFMPEG_PATH wins over PATH.
+        assert_eq!(resolve_ffmpeg_path(None), Some(env_ffmpeg));
+
+        env::remove_var("OGGIFY_FFMPEG_PATH");
+
+        // With neither --ffmpeg-path nor the env var set, fall back to PATH.
+        assert_eq!(resolve_ffmpeg_path(None), Some(path_ffmpeg));
+
+        match original_env {
+            Some(value) => env::set_var("OGGIFY_FFMPEG_PATH", value),
+            None => env::remove_var("OGGIFY_FFMPEG_PATH"),
+        }
+        match original_path {
+            Some(value) => env::set_var("PATH", value),
+            None => env::remove_var("PATH"),
+        }
+    }
+}
+
+/// Transcodes `tagged_ogg` to MP3 at `output_path` via ffmpeg, embedding `cover_jpg` as an
+/// ID3 APIC frame when present. Returns `false` (after logging why) if ffmpeg couldn't be
+/// run or exited with an error, so one bad track doesn't abort the rest of a batch.
+pub fn transcode_mp3(
+    ffmpeg_path: &Path,
+    tagged_ogg: &Path,
+    cover_jpg: Option<&Path>,
+    bitrate_kbps: u32,
+    output_path: &Path,
+) -> bool {
+    let mut cmd = Command::new(ffmpeg_path);
+    cmd.arg("-y").arg("-i").arg(tagged_ogg);
+
+    if let Some(cover) = cover_jpg {
+        cmd.arg("-i")
+            .arg(cover)
+            .arg("-map")
+            .arg("0:a")
+            .arg("-map")
+            .arg("1:v")
+            .arg("-metadata:s:v")
+            .arg("title=Album cover")
+            .arg("-metadata:s:v")
+            .arg("comment=Cover (front)");
+    }
+
+    cmd.arg("-map_metadata")
+        .arg("0:s:0")
+        .arg("-write_id3v2")
+        .arg("1")
+        .arg("-id3v2_version")
+        .arg("3")
+        .arg("-b:a")
+        .arg(format!("{}k", bitrate_kbps))
+        .arg(output_path);
+
+    cmd.stdin(Stdio::piped());
+
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Could not run ffmpeg: {e}");
+            return false;
+        }
+    };
+
+    match child.wait() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            error!("ffmpeg exited with {status}");
+            false
+        }
+        Err(e) => {
+            error!("ffmpeg did not exit cleanly: {e}");
+            false
+        }
+    }
+}
+
+/// Decodes a tagged Vorbis file to PCM and re-encodes it as FLAC, carrying over `tags` as a
+/// VORBIS_COMMENT metadata block and `cover` (if present) as a PICTURE block so FLAC output
+/// is tagged the same as ogg/mp3. No external binary is required; the whole pipeline runs
+/// in-process. Returns `false` (after logging why) if the Vorbis decode or FLAC encode
+/// fails, so one bad track doesn't abort the rest of a batch.
+pub fn transcode_flac(tagged_ogg: &Path, tags: &[(&str, String)], cover: Option<&[u8]>, output_path: &Path) -> bool {
+    let file = match File::open(tagged_ogg) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Can't open tagged Vorbis file: {e}");
+            return false;
+        }
+    };
+    let mut reader = match lewton::inside_ogg::OggStreamReader::new(file) {
+        Ok(reader) => reader,
+        Err(e) => {
+            error!("Not a valid Ogg Vorbis stream: {e}");
+            return false;
+        }
+    };
+
+    let channels = reader.ident_hdr.audio_channels as usize;
+    let sample_rate = reader.ident_hdr.audio_sample_rate as usize;
+
+    let mut samples: Vec<Vec<i32>> = vec![Vec::new(); channels];
+    loop {
+        match reader.read_dec_packet() {
+            Ok(Some(packet)) => {
+                for (ch, channel_samples) in packet.into_iter().enumerate() {
+                    samples[ch].extend(channel_samples.into_iter().map(|s| s as i32));
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("Vorbis decode error: {e}");
+                return false;
+            }
+        }
+    }
+
+    let config = flacenc::config::Encoder::default();
+    let source = flacenc::source::MemSource::from_samples(&samples, channels, 16, sample_rate);
+    let flac_stream = match flacenc::encode_with_fixed_block_size(&config, source, config.block_size) {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("FLAC encode failed: {e:?}");
+            return false;
+        }
+    };
+
+    let mut sink = flacenc::bitsink::ByteSink::new();
+    if let Err(e) = flac_stream.write(&mut sink) {
+        error!("Could not serialize FLAC stream: {e:?}");
+        return false;
+    }
+
+    let tagged = insert_flac_metadata(sink.as_slice(), tags, cover);
+    if let Err(e) = std::fs::write(output_path, tagged) {
+        error!("Could not write FLAC output: {e}");
+        return false;
+    }
+    true
+}
+
+/// Splices a VORBIS_COMMENT metadata block (and a PICTURE block, if `cover` is present) into
+/// a freshly-encoded FLAC stream, which otherwise carries only the STREAMINFO block that
+/// `flacenc` writes.
+fn insert_flac_metadata(flac: &[u8], tags: &[(&str, String)], cover: Option<&[u8]>) -> Vec<u8> {
+    assert_eq!(&flac[0..4], b"fLaC", "flacenc did not produce a standard FLAC stream");
+
+    let mut offset = 4;
+    let (last_header_offset, audio_offset) = loop {
+        let header_offset = offset;
+        let is_last = flac[offset] & 0x80 != 0;
+        let len =
+            u32::from_be_bytes([0, flac[offset + 1], flac[offset + 2], flac[offset + 3]]) as usize;
+        offset += 4 + len;
+        if is_last {
+            break (header_offset, offset);
+        }
+    };
+
+    let mut new_blocks = Vec::new();
+    new_blocks.push(flac_metadata_block(4, &vorbis_comment_block_body(tags), cover.is_none()));
+    if let Some(jpeg) = cover {
+        new_blocks.push(flac_metadata_block(6, &flac_picture_block_body(jpeg), true));
+    }
+
+    let mut out = Vec::with_capacity(flac.len() + new_blocks.iter().map(Vec::len).sum::<usize>());
+    out.extend_from_slice(&flac[0..last_header_offset]);
+    out.push(flac[last_header_offset] & 0x7f); // the old last block no longer is one
+    out.extend_from_slice(&flac[last_header_offset + 1..audio_offset]);
+    for block in new_blocks {
+        out.extend_from_slice(&block);
+    }
+    out.extend_from_slice(&flac[audio_offset..]);
+    out
+}
+
+/// Builds a FLAC metadata block: a 1-byte header (last-block flag + block type) plus a
+/// 3-byte big-endian length, followed by `body`.
+fn flac_metadata_block(block_type: u8, body: &[u8], is_last: bool) -> Vec<u8> {
+    let mut block = Vec::with_capacity(4 + body.len());
+    block.push((u8::from(is_last) << 7) | block_type);
+    block.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    block.extend_from_slice(body);
+    block
+}
+
+/// Builds a FLAC VORBIS_COMMENT metadata block body (block type 4): identical to the Vorbis
+/// comment header format, but without the trailing framing bit.
+fn vorbis_comment_block_body(tags: &[(&str, String)]) -> Vec<u8> {
+    let vendor = b"oggify";
+    let mut body = Vec::new();
+    body.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    body.extend_from_slice(vendor);
+    body.extend_from_slice(&(tags.len() as u32).to_le_bytes());
+    for (key, value) in tags {
+        let comment = format!("{}={}", key, value);
+        body.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        body.extend_from_slice(comment.as_bytes());
+    }
+    body
+}
+
+/// Builds a FLAC PICTURE metadata block body (block type 6, picture type 3 = front cover)
+/// from raw JPEG bytes.
+fn flac_picture_block_body(jpeg: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&3u32.to_be_bytes()); // picture type: front cover
+    let mime = b"image/jpeg";
+    body.extend_from_slice(&(mime.len() as u32).to_be_bytes());
+    body.extend_from_slice(mime);
+    body.extend_from_slice(&0u32.to_be_bytes()); // description length
+    body.extend_from_slice(&0u32.to_be_bytes()); // width (unknown, left as 0)
+    body.extend_from_slice(&0u32.to_be_bytes()); // height (unknown, left as 0)
+    body.extend_from_slice(&0u32.to_be_bytes()); // color depth (unknown, left as 0)
+    body.extend_from_slice(&0u32.to_be_bytes()); // colors used (0 = non-indexed)
+    body.extend_from_slice(&(jpeg.len() as u32).to_be_bytes());
+    body.extend_from_slice(jpeg);
+    body
+}